@@ -1,34 +1,335 @@
+// Targets wgpu 0.7 (BackendBit, SwapChain::get_current_frame, Extent3d::depth). APIs added in
+// later wgpu versions (per-format multisample queries, present-mode queries, `include_spirv!`)
+// aren't available here.
 use async_std::task;
+use image::RgbaImage;
+use std::io::Read;
 use wgpu::{CommandEncoderDescriptor, Device};
 use winit::{dpi::PhysicalSize, event::{Event, WindowEvent}, event_loop::{ControlFlow, EventLoop}, window::Window};
 
 const DEFAULT_WINDOW_WIDTH: u32 = 2048;
 const DEFAULT_WINDOW_HEIGHT: u32 = 2048;
 
-/// Creates a texture that uses MSAA and fits a given swap chain
-fn create_multisampled_framebuffer(
-    device: &wgpu::Device,
-    size: &wgpu::Extent3d,
-    sample_count: u32,
-    format: wgpu::TextureFormat,
-) -> Texture {
-    Texture::new(
-        device,
-        wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: size.width,
-                height: size.height,
-                depth: 1,
+/// Format the scene is rendered in, independent of whatever format the surface prefers.
+const WORKING_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+/// Compiles GLSL source into the raw SPIR-V bytes `wgpu::util::make_spirv` expects.
+fn compile_glsl(source: &str, stage: glsl_to_spirv::ShaderType) -> Vec<u8> {
+    let mut spirv = glsl_to_spirv::compile(source, stage).expect("Failed to compile shader");
+    let mut bytes = Vec::new();
+    spirv
+        .read_to_end(&mut bytes)
+        .expect("Failed to read compiled SPIR-V");
+    bytes
+}
+
+/// A full-screen copy pass used to convert a linear `ResolveBuffer` into the surface's actual
+/// preferred format (e.g. an sRGB swap chain), since the swap chain format can't always be
+/// resolved into directly from the MSAA framebuffer.
+pub struct CopyPipeline {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+}
+
+impl CopyPipeline {
+    pub fn new(device: &Device, dst_format: wgpu::TextureFormat) -> CopyPipeline {
+        let vs_module = device.create_shader_module(wgpu::util::make_spirv(&compile_glsl(
+            include_str!("shaders/copy.vert"),
+            glsl_to_spirv::ShaderType::Vertex,
+        )));
+        let fs_module = device.create_shader_module(wgpu::util::make_spirv(&compile_glsl(
+            include_str!("shaders/copy.frag"),
+            glsl_to_spirv::ShaderType::Fragment,
+        )));
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("copy_srgb_view bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::SampledTexture {
+                        dimension: wgpu::TextureViewDimension::D2,
+                        component_type: wgpu::TextureComponentType::Float,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler { comparison: false },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("copy_srgb_view pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("copy_srgb_view pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex_stage: wgpu::ProgrammableStageDescriptor {
+                module: &vs_module,
+                entry_point: "main",
             },
-            mip_level_count: 1,
-            // array_layer_count: 1,
+            fragment_stage: Some(wgpu::ProgrammableStageDescriptor {
+                module: &fs_module,
+                entry_point: "main",
+            }),
+            rasterization_state: Some(wgpu::RasterizationStateDescriptor::default()),
+            primitive_topology: wgpu::PrimitiveTopology::TriangleList,
+            color_states: &[wgpu::ColorStateDescriptor {
+                format: dst_format,
+                color_blend: wgpu::BlendDescriptor::REPLACE,
+                alpha_blend: wgpu::BlendDescriptor::REPLACE,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+            depth_stencil_state: None,
+            vertex_state: wgpu::VertexStateDescriptor {
+                index_format: wgpu::IndexFormat::Uint16,
+                vertex_buffers: &[],
+            },
+            sample_count: 1,
+            sample_mask: !0,
+            alpha_to_coverage_enabled: false,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("copy_srgb_view sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            lod_min_clamp: -100.0,
+            lod_max_clamp: 100.0,
+            compare: None,
+            anisotropy_clamp: None,
+        });
+
+        CopyPipeline {
+            pipeline,
+            bind_group_layout,
+            sampler,
+        }
+    }
+
+    /// Draws `source` into `destination` through the copy/conversion pipeline.
+    pub fn copy_srgb_view(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        device: &Device,
+        source: &wgpu::TextureView,
+        destination: &wgpu::TextureView,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("copy_srgb_view bind group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(source),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("srgb copy pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                attachment: destination,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+                resolve_target: None,
+            }],
+            depth_stencil_attachment: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+/// Chooses how a resolved frame reaches its final destination.
+pub enum OutputConversion {
+    /// Resolve the MSAA framebuffer directly into the destination view. Only correct when the
+    /// destination's format matches `WORKING_FORMAT`.
+    DirectResolve,
+    /// Resolve into the linear `ResolveBuffer` and run it through a `CopyPipeline` to convert
+    /// into the destination's actual format, e.g. an sRGB swap chain.
+    SrgbCopy(CopyPipeline),
+}
+
+/// Negotiates which present mode the swap chain should use. wgpu 0.7 has no way to query which
+/// present modes a surface actually supports, and `Fifo` is the only mode the spec guarantees
+/// every backend accepts, so that's the safe default; pass `Mailbox` via `override_mode` on
+/// backends you know accept it for lower-latency triple buffering. `override_mode` takes
+/// priority so a replay run can be told which mode the original capture used.
+fn negotiate_present_mode(override_mode: Option<wgpu::PresentMode>) -> wgpu::PresentMode {
+    override_mode.unwrap_or(wgpu::PresentMode::Fifo)
+}
+
+/// Candidate sample counts, highest first. wgpu 0.7 has no adapter/device query for which counts
+/// a format supports (that arrived in later wgpu versions), so this isn't a queried result — it's
+/// 8x (the highest MSAA level in common use), falling back through the counts the spec guarantees
+/// every backend supports (4x, then 1x) if the caller asks for less.
+const CANDIDATE_SAMPLE_COUNTS: [u32; 3] = [8, 4, 1];
+
+/// Picks the highest candidate sample count that is still `<= requested`, so we never return more
+/// samples than were asked for; the one exception is a request of 0, which still yields 1.
+fn choose_sample_count(requested: u32) -> u32 {
+    CANDIDATE_SAMPLE_COUNTS
+        .iter()
+        .copied()
+        .find(|&count| count <= requested)
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_the_next_multiple() {
+        assert_eq!(align_up(8192, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT), 8192);
+        assert_eq!(align_up(8193, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT), 8448);
+        assert_eq!(align_up(1, 64), 64);
+        assert_eq!(align_up(64, 64), 64);
+        assert_eq!(align_up(65, 64), 128);
+    }
+
+    #[test]
+    fn choose_sample_count_never_exceeds_requested_except_minimum_of_one() {
+        assert_eq!(choose_sample_count(8), 8);
+        assert_eq!(choose_sample_count(6), 4);
+        assert_eq!(choose_sample_count(4), 4);
+        assert_eq!(choose_sample_count(2), 1);
+        assert_eq!(choose_sample_count(1), 1);
+        assert_eq!(choose_sample_count(0), 1);
+    }
+}
+
+/// The MSAA render target that the scene is actually drawn into.
+pub struct FrameBuffer {
+    pub texture: Texture,
+    pub size: wgpu::Extent3d,
+    pub sample_count: u32,
+}
+
+impl FrameBuffer {
+    pub fn new(device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat, sample_count: u32) -> FrameBuffer {
+        FrameBuffer {
+            texture: Self::create_texture(device, size, format, sample_count),
+            size,
             sample_count,
-            dimension: wgpu::TextureDimension::D2,
-            format, //sc_desc.format,
-            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
-            label: Some("MSAA Framebuffer"),
-        },
-    )
+        }
+    }
+
+    fn create_texture(device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat, sample_count: u32) -> Texture {
+        Texture::new(
+            device,
+            wgpu::TextureDescriptor {
+                size,
+                mip_level_count: 1,
+                sample_count,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+                label: Some("MSAA framebuffer"),
+            },
+        )
+    }
+
+    pub fn resize(&mut self, device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat) {
+        self.texture = Self::create_texture(device, size, format, self.sample_count);
+        self.size = size;
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+}
+
+/// A single-sample, sampleable target that the `FrameBuffer` is resolved into on the
+/// `OutputConversion::SrgbCopy` path, before the copy pipeline draws it into its final
+/// destination. `OutputConversion::DirectResolve` does not use it.
+pub struct ResolveBuffer {
+    pub texture: Texture,
+    pub size: wgpu::Extent3d,
+}
+
+impl ResolveBuffer {
+    pub fn new(device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat) -> ResolveBuffer {
+        ResolveBuffer {
+            texture: Self::create_texture(device, size, format),
+            size,
+        }
+    }
+
+    fn create_texture(device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat) -> Texture {
+        Texture::new(
+            device,
+            wgpu::TextureDescriptor {
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+                label: Some("Resolve buffer"),
+            },
+        )
+    }
+
+    pub fn resize(&mut self, device: &Device, size: wgpu::Extent3d, format: wgpu::TextureFormat) {
+        self.texture = Self::create_texture(device, size, format);
+        self.size = size;
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+}
+
+/// Rounds `value` up to the next multiple of `alignment`.
+fn align_up(value: u32, alignment: u32) -> u32 {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// Byte layout of a row-padded buffer used to copy a texture back to the CPU.
+///
+/// wgpu requires `bytes_per_row` in a buffer-texture copy to be a multiple of
+/// `COPY_BYTES_PER_ROW_ALIGNMENT`, so the padded stride is usually wider than the tightly
+/// packed image data and the padding has to be stripped back out after reading.
+struct BufferDimensions {
+    height: u32,
+    unpadded_bytes_per_row: u32,
+    padded_bytes_per_row: u32,
+}
+
+impl BufferDimensions {
+    fn new(width: u32, height: u32) -> Self {
+        let bytes_per_pixel = std::mem::size_of::<u32>() as u32;
+        let unpadded_bytes_per_row = width * bytes_per_pixel;
+        let padded_bytes_per_row = align_up(unpadded_bytes_per_row, wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        Self {
+            height,
+            unpadded_bytes_per_row,
+            padded_bytes_per_row,
+        }
+    }
 }
 
 pub struct Texture {
@@ -61,6 +362,293 @@ impl Texture {
             view,
         }
     }
+
+    /// Reads the texture contents back from the GPU and returns them as an RGBA image.
+    ///
+    /// The texture must have been created with `TextureUsage::COPY_SRC`. This is the basis for
+    /// `trace`-driven regression captures: render a frame into an offscreen texture, then dump
+    /// it to disk instead of presenting it to a window.
+    pub fn read_to_image(&self, device: &Device, queue: &wgpu::Queue) -> RgbaImage {
+        let width = self.descriptor.size.width;
+        let height = self.descriptor.size.height;
+        let dimensions = BufferDimensions::new(width, height);
+
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Texture readback buffer"),
+            size: (dimensions.padded_bytes_per_row * dimensions.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("Texture readback encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::TextureCopyView {
+                texture: &self.buffer,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::BufferCopyView {
+                buffer: &readback_buffer,
+                layout: wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: dimensions.padded_bytes_per_row,
+                    rows_per_image: 0,
+                },
+            },
+            self.descriptor.size,
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let buffer_slice = readback_buffer.slice(..);
+        let map_future = buffer_slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        task::block_on(map_future).expect("Failed to map texture readback buffer");
+
+        let padded_data = buffer_slice.get_mapped_range();
+        let unpadded_bytes_per_row = dimensions.unpadded_bytes_per_row as usize;
+        let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * dimensions.height as usize);
+        for row in padded_data.chunks(dimensions.padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+        }
+        drop(padded_data);
+        readback_buffer.unmap();
+
+        // Bgra formats store blue before red; swap channels back to RGBA for the output image.
+        if matches!(
+            self.descriptor.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            for bgra in pixels.chunks_exact_mut(4) {
+                bgra.swap(0, 2);
+            }
+        }
+
+        RgbaImage::from_raw(width, height, pixels)
+            .expect("Readback buffer did not match the texture dimensions")
+    }
+}
+
+/// A single rendered frame obtained from a `RenderTarget`, exposing the view to render into.
+trait RenderTargetFrame {
+    fn view(&self) -> &wgpu::TextureView;
+}
+
+/// Something the renderer can draw a frame into: either a window's swap chain or an offscreen
+/// texture. This lets the same render-pass code run for on-screen presentation and for
+/// `trace`-driven offscreen captures.
+trait RenderTarget<'a> {
+    type Frame: RenderTargetFrame;
+
+    fn resize(&mut self, device: &Device, width: u32, height: u32);
+    fn format(&self) -> wgpu::TextureFormat;
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn get_next_frame(&'a mut self) -> Self::Frame;
+}
+
+struct SwapChainTargetFrame(wgpu::SwapChainFrame);
+
+impl RenderTargetFrame for SwapChainTargetFrame {
+    fn view(&self) -> &wgpu::TextureView {
+        &self.0.output.view
+    }
+}
+
+/// Renders to a window via its `Surface`/`SwapChain`, rebuilding the swap chain on resize.
+pub struct SwapChainTarget {
+    surface: wgpu::Surface,
+    descriptor: wgpu::SwapChainDescriptor,
+    swap_chain: wgpu::SwapChain,
+}
+
+impl SwapChainTarget {
+    pub fn new(device: &Device, surface: wgpu::Surface, descriptor: wgpu::SwapChainDescriptor) -> SwapChainTarget {
+        let swap_chain = device.create_swap_chain(&surface, &descriptor);
+        SwapChainTarget {
+            surface,
+            descriptor,
+            swap_chain,
+        }
+    }
+
+    /// The present mode negotiated at construction time. Resizes rebuild the swap chain with
+    /// this same mode rather than renegotiating it.
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.descriptor.present_mode
+    }
+}
+
+impl<'a> RenderTarget<'a> for SwapChainTarget {
+    type Frame = SwapChainTargetFrame;
+
+    fn resize(&mut self, device: &Device, width: u32, height: u32) {
+        self.descriptor.width = width;
+        self.descriptor.height = height;
+        self.swap_chain = device.create_swap_chain(&self.surface, &self.descriptor);
+    }
+
+    fn format(&self) -> wgpu::TextureFormat {
+        self.descriptor.format
+    }
+
+    fn width(&self) -> u32 {
+        self.descriptor.width
+    }
+
+    fn height(&self) -> u32 {
+        self.descriptor.height
+    }
+
+    fn get_next_frame(&'a mut self) -> SwapChainTargetFrame {
+        SwapChainTargetFrame(self.swap_chain.get_current_frame().unwrap())
+    }
+}
+
+struct TextureTargetFrame<'a>(&'a wgpu::TextureView);
+
+impl<'a> RenderTargetFrame for TextureTargetFrame<'a> {
+    fn view(&self) -> &wgpu::TextureView {
+        self.0
+    }
+}
+
+/// An offscreen render target that can be read back to the CPU, e.g. to dump a PNG capture
+/// instead of presenting to a window.
+pub struct TextureTarget {
+    pub texture: Texture,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+}
+
+impl TextureTarget {
+    pub fn new(device: &Device, format: wgpu::TextureFormat, width: u32, height: u32) -> TextureTarget {
+        TextureTarget {
+            texture: Self::create_texture(device, format, width, height),
+            format,
+            width,
+            height,
+        }
+    }
+
+    fn create_texture(device: &Device, format: wgpu::TextureFormat, width: u32, height: u32) -> Texture {
+        Texture::new(
+            device,
+            wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+                label: Some("Offscreen capture texture"),
+            },
+        )
+    }
+}
+
+impl<'a> RenderTarget<'a> for TextureTarget {
+    type Frame = TextureTargetFrame<'a>;
+
+    fn resize(&mut self, device: &Device, width: u32, height: u32) {
+        self.texture = Self::create_texture(device, self.format, width, height);
+        self.width = width;
+        self.height = height;
+    }
+
+    fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn get_next_frame(&'a mut self) -> TextureTargetFrame<'a> {
+        TextureTargetFrame(&self.texture.view)
+    }
+}
+
+/// Renders the standard msaa-clear / resolve pass sequence into `target`, then delivers the
+/// result to the target's actual format according to `conversion`.
+fn render_frame<'a, T: RenderTarget<'a>>(
+    target: &'a mut T,
+    device: &Device,
+    queue: &wgpu::Queue,
+    frame_buffer: &FrameBuffer,
+    resolve_buffer: &ResolveBuffer,
+    conversion: &OutputConversion,
+) {
+    let frame = target.get_next_frame();
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("Frame encoder"),
+    });
+
+    let framebuffer_target = frame.view();
+    let multisample_target = frame_buffer.view();
+    let resolve_target = resolve_buffer.view();
+
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("some msaa pass"),
+        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+            attachment: multisample_target,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                store: true,
+            },
+            resolve_target: None,
+        }],
+        depth_stencil_attachment: None,
+    });
+
+    match conversion {
+        OutputConversion::DirectResolve => {
+            // Destination format already matches the working format, so resolve straight into
+            // it; resolving into `resolve_buffer` first would just be a second, unused resolve.
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("resolve pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: multisample_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                    resolve_target: Some(framebuffer_target),
+                }],
+                depth_stencil_attachment: None,
+            });
+        }
+        OutputConversion::SrgbCopy(copy_pipeline) => {
+            // Resolve into the linear buffer first so the copy pipeline has a sampled source,
+            // then convert it into the destination's actual format.
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("resolve pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+                    attachment: multisample_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                    resolve_target: Some(resolve_target),
+                }],
+                depth_stencil_attachment: None,
+            });
+            copy_pipeline.copy_srgb_view(&mut encoder, device, resolve_target, framebuffer_target);
+        }
+    }
+
+    queue.submit(std::iter::once(encoder.finish()));
 }
 
 fn main() {
@@ -92,32 +680,64 @@ fn main() {
     ));
     let size = window.inner_size();
 
-    let mut swap_chain_desc = wgpu::SwapChainDescriptor {
+    let window_surface = unsafe { instance.create_surface(&window) };
+    let surface_format = adapter
+        .get_swap_chain_preferred_format(&window_surface)
+        .unwrap_or(wgpu::TextureFormat::Bgra8Unorm);
+    let present_mode = negotiate_present_mode(None);
+    println!("Using present mode {:?}", present_mode);
+
+    let swap_chain_desc = wgpu::SwapChainDescriptor {
         usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
-        format: wgpu::TextureFormat::Bgra8Unorm,
+        format: surface_format,
         width: size.width,
         height: size.height,
-        present_mode: wgpu::PresentMode::Fifo,
+        present_mode,
     };
 
-    let window_surface = unsafe { instance.create_surface(&window) };
-    let mut swap_chain = device.create_swap_chain(&window_surface, &swap_chain_desc);
+    let mut render_target = SwapChainTarget::new(&device, window_surface, swap_chain_desc);
 
-    let sample_count = 8;
+    let sample_count = choose_sample_count(8);
 
-    let window_extent = wgpu::Extent3d {
-        width: swap_chain_desc.width,
-        height: swap_chain_desc.height,
-        depth: 1,
-    };
-    
-    let mut multisample_texture = create_multisampled_framebuffer(
+    let mut frame_buffer = FrameBuffer::new(
         &device,
-        &window_extent,
+        wgpu::Extent3d {
+            width: render_target.width(),
+            height: render_target.height(),
+            depth: 1,
+        },
+        WORKING_FORMAT,
         sample_count,
-        swap_chain_desc.format,
+    );
+    let mut resolve_buffer = ResolveBuffer::new(
+        &device,
+        wgpu::Extent3d {
+            width: render_target.width(),
+            height: render_target.height(),
+            depth: 1,
+        },
+        WORKING_FORMAT,
     );
 
+    // The swap chain's preferred format is often sRGB while the scene renders in linear space, so
+    // route through the copy pipeline whenever the two formats don't already match.
+    let window_conversion = if surface_format == WORKING_FORMAT {
+        OutputConversion::DirectResolve
+    } else {
+        OutputConversion::SrgbCopy(CopyPipeline::new(&device, surface_format))
+    };
+
+    // Offscreen target used to dump a one-off PNG capture of the first rendered frame, useful
+    // for `trace`-driven regression tests that don't want to rely on a visible window.
+    let mut capture_target = TextureTarget::new(
+        &device,
+        WORKING_FORMAT,
+        render_target.width(),
+        render_target.height(),
+    );
+    let capture_conversion = OutputConversion::DirectResolve;
+    let mut captured_first_frame = false;
+
     let mut init_encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
         label: Some("Init encoder"),
     });
@@ -126,6 +746,7 @@ fn main() {
     event_loop.run(move |event, _, control_flow| {
 
         let mut rebuild_swapchain = false;
+        let mut new_size = (render_target.width(), render_target.height());
         match event {
             Event::MainEventsCleared => {
             }
@@ -135,8 +756,7 @@ fn main() {
                 }
                 WindowEvent::Resized(size) => {
                     rebuild_swapchain = true;
-                    swap_chain_desc.width = size.width;
-                    swap_chain_desc.height = size.height;
+                    new_size = (size.width, size.height);
                 }
                 WindowEvent::ScaleFactorChanged { .. } => {
                     rebuild_swapchain = true;
@@ -145,76 +765,32 @@ fn main() {
                 _ => {}
             }
             Event::RedrawRequested(_) => {
-                let swapchain_output = swap_chain.get_current_frame().unwrap();
-
-                let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-                    label: Some("Frame encoder"),
-                });
-
-                let framebuffer_target = &swapchain_output.output.view;
-                let multisample_target = &multisample_texture.view;
-
-                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("some msaa pass"),
-                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: multisample_target,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
-                            store: true,
-                        },
-                        resolve_target: None,
-                    }],
-                    depth_stencil_attachment: None,
-                });
-
-                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("resolve pass pass"),
-                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: multisample_target,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: true,
-                        },
-                        resolve_target: Some(framebuffer_target),
-                    }],
-                    depth_stencil_attachment: None,
-                });
-
-                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                    label: Some("non-msaa pass"),
-                    color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: framebuffer_target,
-                        ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Load,
-                            store: true,
-                        },
-                        resolve_target: None,
-                    }],
-                    depth_stencil_attachment: None,
-                });
-
-                queue.submit(std::iter::once(encoder.finish()));
+                render_frame(&mut render_target, &device, &queue, &frame_buffer, &resolve_buffer, &window_conversion);
+
+                if !captured_first_frame {
+                    render_frame(&mut capture_target, &device, &queue, &frame_buffer, &resolve_buffer, &capture_conversion);
+                    let image = capture_target.texture.read_to_image(&device, &queue);
+                    image.save("capture.png").expect("Failed to write capture.png");
+                    captured_first_frame = true;
+                }
             }
             _ => {}
         }
 
-        let window_extent = wgpu::Extent3d {
-            width: swap_chain_desc.width,
-            height: swap_chain_desc.height,
-            depth: 1,
-        };
-
         if rebuild_swapchain {
-            println!("Rebuilding swap chain");
-            swap_chain = device.create_swap_chain(&window_surface, &swap_chain_desc);
-            multisample_texture = create_multisampled_framebuffer(
-                &device,
-                &window_extent,
-                sample_count,
-                swap_chain_desc.format,
-            );
+            println!("Rebuilding swap chain (present mode {:?})", render_target.present_mode());
+            let (width, height) = new_size;
+            render_target.resize(&device, width, height);
+            let extent = wgpu::Extent3d { width, height, depth: 1 };
+            frame_buffer.resize(&device, extent, WORKING_FORMAT);
+            resolve_buffer.resize(&device, extent, WORKING_FORMAT);
+            // Keep the capture target in lockstep so a resize before the first capture doesn't
+            // leave it at a stale size when `frame_buffer`/`resolve_buffer` are resolved into it.
+            if !captured_first_frame {
+                capture_target.resize(&device, width, height);
+            }
         }
 
-        
+
     });
 }